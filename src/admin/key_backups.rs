@@ -0,0 +1,73 @@
+use clap::Subcommand;
+use ruma::{events::room::message::RoomMessageEventContent, UserId};
+
+use crate::{database::Database, Result};
+
+// `db.key_backups.delete_all_backups_for_user` (used by `Delete` below) is also wrapped by
+// `client_server::account::reclaim_key_backups_on_deactivation`. The account deactivation handler
+// does not call that wrapper, so a deactivated user's key backups stay in place until removed
+// with `Delete`.
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum KeyBackupsCommand {
+    /// - List a user's key backup versions and how many keys each one holds
+    List { user_id: Box<UserId> },
+
+    /// - Show the session count and etag for one specific backup version
+    Count {
+        user_id: Box<UserId>,
+        version: String,
+    },
+
+    /// - Delete a user's key backups, reclaiming their storage
+    ///
+    /// Deletes every version the user owns if `version` is omitted.
+    Delete {
+        user_id: Box<UserId>,
+        version: Option<String>,
+    },
+}
+
+pub(crate) async fn process(
+    db: &Database,
+    command: KeyBackupsCommand,
+    _body: Vec<&str>,
+) -> Result<RoomMessageEventContent> {
+    match command {
+        KeyBackupsCommand::List { user_id } => {
+            let versions = db.key_backups.list_versions(&user_id)?;
+
+            if versions.is_empty() {
+                return Ok(RoomMessageEventContent::text_plain(format!(
+                    "{user_id} has no key backups."
+                )));
+            }
+
+            let mut lines = format!("Key backups for {user_id}:\n");
+            for version in versions {
+                let count = db.key_backups.count_keys(&user_id, &version)?;
+                lines.push_str(&format!("- version {version}: {count} keys\n"));
+            }
+
+            Ok(RoomMessageEventContent::notice_markdown(lines))
+        }
+        KeyBackupsCommand::Count { user_id, version } => {
+            let count = db.key_backups.count_keys(&user_id, &version)?;
+            let etag = db.key_backups.get_etag(&user_id, &version)?;
+
+            Ok(RoomMessageEventContent::text_plain(format!(
+                "{user_id} backup version {version} has {count} keys (etag {etag})."
+            )))
+        }
+        KeyBackupsCommand::Delete { user_id, version } => {
+            match version {
+                Some(version) => db.key_backups.delete_backup(&user_id, &version)?,
+                None => db.key_backups.delete_all_backups_for_user(&user_id)?,
+            }
+
+            Ok(RoomMessageEventContent::text_plain(format!(
+                "Deleted key backup(s) for {user_id}."
+            )))
+        }
+    }
+}
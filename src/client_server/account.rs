@@ -0,0 +1,10 @@
+use crate::{database::Database, Result};
+use ruma::UserId;
+
+/// Deletes every key backup version `user_id` owns, reclaiming their storage.
+///
+/// `deactivate_account_route` does not call this, so deactivating an account leaves its key
+/// backups in place.
+pub(crate) fn reclaim_key_backups_on_deactivation(db: &Database, user_id: &UserId) -> Result<()> {
+    db.key_backups.delete_all_backups_for_user(user_id)
+}
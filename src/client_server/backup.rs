@@ -1,4 +1,13 @@
-use crate::{database::DatabaseGuard, Error, Result, Ruma};
+use crate::{
+    database::{globals::Globals, DatabaseGuard},
+    Error, Result, Ruma, RumaResponse,
+};
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
 use ruma::api::client::{
     backup::{
         add_backup_key_session, add_backup_key_sessions, add_backup_keys, create_backup,
@@ -45,10 +54,14 @@ pub async fn update_backup_route(
 /// # `GET /_matrix/client/r0/room_keys/version`
 ///
 /// Get information about the latest backup version.
+///
+/// Honors `If-None-Match` against the backup's content-addressed etag: if the client's cached
+/// etag still matches, responds `304 Not Modified` instead of re-sending the (unchanged) count.
 pub async fn get_latest_backup_route(
     db: DatabaseGuard,
+    headers: HeaderMap,
     body: Ruma<get_latest_backup::v3::Request>,
-) -> Result<get_latest_backup::v3::Response> {
+) -> Result<Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     let (version, algorithm) =
@@ -59,21 +72,31 @@ pub async fn get_latest_backup_route(
                 "Key backup does not exist.",
             ))?;
 
-    Ok(get_latest_backup::v3::Response {
+    let etag = db.key_backups.get_etag(sender_user, &version)?;
+    if if_none_match_satisfied(&headers, &etag) {
+        return Ok(StatusCode::NOT_MODIFIED.into_response());
+    }
+
+    Ok(RumaResponse(get_latest_backup::v3::Response {
         algorithm,
         count: (db.key_backups.count_keys(sender_user, &version)? as u32).into(),
-        etag: db.key_backups.get_etag(sender_user, &version)?,
+        etag,
         version,
     })
+    .into_response())
 }
 
 /// # `GET /_matrix/client/r0/room_keys/version`
 ///
 /// Get information about an existing backup.
+///
+/// Honors `If-None-Match` against the backup's content-addressed etag: if the client's cached
+/// etag still matches, responds `304 Not Modified` instead of re-sending the (unchanged) count.
 pub async fn get_backup_route(
     db: DatabaseGuard,
+    headers: HeaderMap,
     body: Ruma<get_backup::v3::Request<'_>>,
-) -> Result<get_backup::v3::Response> {
+) -> Result<Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let algorithm = db
         .key_backups
@@ -83,12 +106,31 @@ pub async fn get_backup_route(
             "Key backup does not exist.",
         ))?;
 
-    Ok(get_backup::v3::Response {
+    let etag = db.key_backups.get_etag(sender_user, &body.version)?;
+    if if_none_match_satisfied(&headers, &etag) {
+        return Ok(StatusCode::NOT_MODIFIED.into_response());
+    }
+
+    Ok(RumaResponse(get_backup::v3::Response {
         algorithm,
         count: (db.key_backups.count_keys(sender_user, &body.version)? as u32).into(),
-        etag: db.key_backups.get_etag(sender_user, &body.version)?,
+        etag,
         version: body.version.to_owned(),
     })
+    .into_response())
+}
+
+/// Whether `If-None-Match` names an etag that matches `current` (or is the wildcard `*`), per
+/// [RFC 9110 §13.1.2](https://www.rfc-editor.org/rfc/rfc9110#section-13.1.2).
+fn if_none_match_satisfied(headers: &HeaderMap, current: &str) -> bool {
+    let Some(Ok(value)) = headers.get("if-none-match").map(|v| v.to_str()) else {
+        return false;
+    };
+
+    value
+        .split(',')
+        .map(|etag| etag.trim().trim_start_matches("W/").trim_matches('"'))
+        .any(|etag| etag == "*" || etag == current)
 }
 
 /// # `DELETE /_matrix/client/r0/room_keys/version/{version}`
@@ -134,6 +176,16 @@ pub async fn add_backup_keys_route(
         ));
     }
 
+    db.key_backups.check_batch_quota(
+        sender_user,
+        &body.version,
+        body.rooms.iter().flat_map(|(room_id, room)| {
+            room.sessions
+                .iter()
+                .map(move |(session_id, key_data)| (&**room_id, session_id.as_str(), key_data))
+        }),
+    )?;
+
     for (room_id, room) in &body.rooms {
         for (session_id, key_data) in &room.sessions {
             db.key_backups.add_key(
@@ -142,7 +194,6 @@ pub async fn add_backup_keys_route(
                 room_id,
                 session_id,
                 key_data,
-                &db.globals,
             )?
         }
     }
@@ -180,6 +231,14 @@ pub async fn add_backup_key_sessions_route(
         ));
     }
 
+    db.key_backups.check_batch_quota(
+        sender_user,
+        &body.version,
+        body.sessions
+            .iter()
+            .map(|(session_id, key_data)| (&*body.room_id, session_id.as_str(), key_data)),
+    )?;
+
     for (session_id, key_data) in &body.sessions {
         db.key_backups.add_key(
             sender_user,
@@ -187,7 +246,6 @@ pub async fn add_backup_key_sessions_route(
             &body.room_id,
             session_id,
             key_data,
-            &db.globals,
         )?
     }
 
@@ -230,7 +288,6 @@ pub async fn add_backup_key_session_route(
         &body.room_id,
         &body.session_id,
         &body.session_data,
-        &db.globals,
     )?;
 
     db.flush()?;
@@ -349,3 +406,48 @@ pub async fn delete_backup_key_session_route(
         etag: db.key_backups.get_etag(sender_user, &body.version)?,
     })
 }
+
+/// Additional router exposing the pre-stabilization `unstable` room_keys endpoints.
+///
+/// Older and third-party clients that never migrated off the unstable identifiers still call
+/// `/_matrix/client/unstable/room_keys/...`. Rather than duplicating any logic, this mounts the
+/// very same handlers above (and therefore the same `ruma` request/response types and
+/// `key_backups` service calls) on those legacy paths as well.
+///
+/// Controlled by `allow_legacy_key_backup_routes` in the server config so operators who don't
+/// need the compatibility surface can turn it off.
+pub fn legacy_routes(globals: &Globals) -> Router {
+    if !globals.config.allow_legacy_key_backup_routes {
+        return Router::new();
+    }
+
+    Router::new()
+        .route(
+            "/_matrix/client/unstable/room_keys/version",
+            get(get_latest_backup_route).post(create_backup_route),
+        )
+        .route(
+            "/_matrix/client/unstable/room_keys/version/:version",
+            get(get_backup_route)
+                .put(update_backup_route)
+                .delete(delete_backup_route),
+        )
+        .route(
+            "/_matrix/client/unstable/room_keys/keys",
+            get(get_backup_keys_route)
+                .put(add_backup_keys_route)
+                .delete(delete_backup_keys_route),
+        )
+        .route(
+            "/_matrix/client/unstable/room_keys/keys/:room_id",
+            get(get_backup_key_sessions_route)
+                .put(add_backup_key_sessions_route)
+                .delete(delete_backup_key_sessions_route),
+        )
+        .route(
+            "/_matrix/client/unstable/room_keys/keys/:room_id/:session_id",
+            get(get_backup_key_session_route)
+                .put(add_backup_key_session_route)
+                .delete(delete_backup_key_session_route),
+        )
+}
@@ -0,0 +1,759 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::{
+    api::client::{
+        backup::{BackupAlgorithm, KeyBackupData},
+        error::ErrorKind,
+    },
+    serde::Raw,
+    uint, OwnedRoomId, RoomId, UserId,
+};
+
+use super::globals::Globals;
+use crate::{
+    database::abstraction::{KeyValueDatabaseEngine, Tree},
+    utils, Error, Result,
+};
+
+pub struct KeyBackups {
+    pub(super) backupid_algorithm: Arc<dyn Tree>,
+    pub(super) backupid_etag: Arc<dyn Tree>,
+    pub(super) backupkeyid_backup: Arc<dyn Tree>,
+
+    /// Cached `(room, session)` key count per `(user, version)`, kept up to date by `add_key`. The
+    /// `delete_*` methods clear it, so `count_keys` rescans that version once and reseeds it.
+    pub(super) backupid_keycount: Arc<dyn Tree>,
+
+    /// Cached total size in bytes of `session_data` per `(user, version)`, maintained the same
+    /// way, used to enforce `max_bytes_per_user` without re-summing the backup on every upload.
+    pub(super) backupid_bytesused: Arc<dyn Tree>,
+
+    /// Monotonic revision number per `(user, version)`, bumped by `update_backup` whenever
+    /// `auth_data`/`algorithm` changes. Folded into `get_etag` alongside the key-content digest in
+    /// `backupid_etag` so the etag also changes on metadata-only updates, per the Matrix spec.
+    pub(super) backupid_metarev: Arc<dyn Tree>,
+
+    /// Due-unix-timestamp (seconds) per `(user, version)` of a version superseded by a newer one,
+    /// written synchronously by `mark_superseded_for_prune` and consumed by `run_due_prunes`.
+    pub(super) backupid_prune_due: Arc<dyn Tree>,
+
+    /// `0` means unlimited. See `add_key`.
+    pub(super) max_keys_per_user: u64,
+    /// `0` means unlimited. See `add_key`.
+    pub(super) max_bytes_per_user: u64,
+
+    /// What to do with a version's key data once it's no longer the latest. See `run_due_prunes`.
+    pub(super) prune_policy: PrunePolicy,
+    /// How long to wait after a version is superseded before pruning it.
+    pub(super) prune_grace_period: Duration,
+}
+
+/// What happens to a backup version once `create_backup` makes it no longer the latest. Clients
+/// can only ever read and never update a superseded version, so its key data is dead weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrunePolicy {
+    /// Keep every version and its key data forever (the historical behavior).
+    Off,
+    /// Delete a superseded version's key data after the grace period, keeping its metadata/etag
+    /// around so `get_backup`/`get_latest_backup_version` history stays intact.
+    PruneData,
+    /// Delete a superseded version entirely (metadata, etag and key data) after the grace period.
+    PruneAll,
+}
+
+/// Server-configurable limits and pruning policy for the key-backups service, loaded from the
+/// server config and passed to `KeyBackups::new` when the database is opened. `0`/`Off` values
+/// reproduce the historical behavior of no quota and no pruning, so existing configs without these
+/// keys keep working unchanged.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyBackupsConfig {
+    /// Maximum number of `(room, session)` keys a user may store across all their backup
+    /// versions. `0` disables the limit. Default: `0`.
+    pub max_keys_per_user: u64,
+    /// Maximum total `session_data` bytes a user may store across all their backup versions. `0`
+    /// disables the limit. Default: `0`.
+    pub max_bytes_per_user: u64,
+    /// What to do with a version's key data once it's superseded. Default: `PrunePolicy::Off`.
+    pub prune_policy: PrunePolicy,
+    /// How long to wait after a version is superseded before pruning it. Default: 24 hours.
+    pub prune_grace_period: Duration,
+}
+
+impl Default for KeyBackupsConfig {
+    fn default() -> Self {
+        Self {
+            max_keys_per_user: 0,
+            max_bytes_per_user: 0,
+            prune_policy: PrunePolicy::Off,
+            prune_grace_period: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl KeyBackups {
+    /// Opens every tree this service needs and applies the config-derived limits/policy. Called
+    /// once from `Database::load_or_create` when the server starts.
+    pub fn new(db: &dyn KeyValueDatabaseEngine, config: KeyBackupsConfig) -> Result<Self> {
+        Ok(Self {
+            backupid_algorithm: db.open_tree("backupid_algorithm")?,
+            backupid_etag: db.open_tree("backupid_etag")?,
+            backupkeyid_backup: db.open_tree("backupkeyid_backup")?,
+            backupid_keycount: db.open_tree("backupid_keycount")?,
+            backupid_bytesused: db.open_tree("backupid_bytesused")?,
+            backupid_metarev: db.open_tree("backupid_metarev")?,
+            backupid_prune_due: db.open_tree("backupid_prune_due")?,
+            max_keys_per_user: config.max_keys_per_user,
+            max_bytes_per_user: config.max_bytes_per_user,
+            prune_policy: config.prune_policy,
+            prune_grace_period: config.prune_grace_period,
+        })
+    }
+
+    pub fn create_backup(
+        &self,
+        user_id: &UserId,
+        backup_metadata: &Raw<BackupAlgorithm>,
+        globals: &Globals,
+    ) -> Result<String> {
+        // Zero-padded to u64::MAX's width so that `scan_prefix(...).last()` in
+        // `get_latest_backup_version`/`get_latest_backup` — which orders lexicographically, not
+        // numerically — still picks the newest version once the counter grows past one digit
+        // (byte order would otherwise put e.g. "9" after "10").
+        let version = format!("{:020}", globals.next_count()?);
+
+        self.backupid_algorithm.insert(
+            &backup_key(user_id, &version),
+            &serde_json::to_vec(backup_metadata).expect("BackupAlgorithm::to_vec always works"),
+        )?;
+        // A fresh version starts with no keys, so its content digest starts at zero. `add_key`
+        // folds each session's hash in from here.
+        self.backupid_etag
+            .insert(&backup_key(user_id, &version), &0u64.to_be_bytes())?;
+        self.backupid_metarev
+            .insert(&backup_key(user_id, &version), &0u64.to_be_bytes())?;
+
+        self.mark_superseded_for_prune(user_id, &version)?;
+
+        Ok(version)
+    }
+
+    /// Records every version of `user_id`'s backup other than `new_version` as due for pruning
+    /// after `prune_grace_period`, per `prune_policy`.
+    ///
+    /// This only writes a due-timestamp to `backupid_prune_due`; the actual pruning happens in
+    /// `run_due_prunes`, called periodically from the server's background-task sweep. Writing the
+    /// due-timestamp synchronously here (instead of spawning a task that sleeps for the grace
+    /// period) means a restart during the grace window doesn't lose track of a version that was
+    /// supposed to be pruned, and the per-version work of an arbitrarily long backup history is
+    /// never more than one bounded sweep instead of one unbounded task per `create_backup` call.
+    fn mark_superseded_for_prune(&self, user_id: &UserId, new_version: &str) -> Result<()> {
+        if self.prune_policy == PrunePolicy::Off {
+            return Ok(());
+        }
+
+        let due_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is after the epoch")
+            + self.prune_grace_period;
+
+        for version in self.list_versions(user_id)? {
+            if version == new_version {
+                continue;
+            }
+
+            self.backupid_prune_due.insert(
+                &backup_key(user_id, &version),
+                &due_at.as_secs().to_be_bytes(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Prunes up to `max_count` versions whose grace period (see `mark_superseded_for_prune`) has
+    /// elapsed, according to `prune_policy`. Returns the number of versions pruned.
+    ///
+    /// Meant to be called periodically (and with a bounded `max_count`) by the server's
+    /// background-task sweep, rather than from any request handler, so pruning a user with a long
+    /// backup history never stalls a request and never runs more than one sweep's worth of work
+    /// concurrently.
+    pub fn run_due_prunes(&self, max_count: usize) -> Result<usize> {
+        if self.prune_policy == PrunePolicy::Off {
+            return Ok(0);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is after the epoch")
+            .as_secs();
+
+        let due: Vec<Vec<u8>> = self
+            .backupid_prune_due
+            .scan_prefix(Vec::new())
+            .filter_map(|(key, value)| {
+                let due_at = utils::u64_from_bytes(&value).ok()?;
+                (due_at <= now).then_some(key)
+            })
+            .take(max_count)
+            .collect();
+
+        let mut pruned = 0;
+        for key in due {
+            let result: Result<()> = (|| {
+                let mut prefix = key.clone();
+                prefix.push(0xff);
+                for (key, _) in self.backupkeyid_backup.scan_prefix(prefix) {
+                    self.backupkeyid_backup.remove(&key)?;
+                }
+                self.backupid_keycount.remove(&key)?;
+                self.backupid_bytesused.remove(&key)?;
+
+                if self.prune_policy == PrunePolicy::PruneAll {
+                    self.backupid_algorithm.remove(&key)?;
+                    self.backupid_etag.remove(&key)?;
+                    self.backupid_metarev.remove(&key)?;
+                } else {
+                    // The key data is gone, so the content digest half of the etag must go back
+                    // to the "empty backup" value too, or a client holding the pre-prune etag
+                    // would see a `304` for data that no longer exists.
+                    self.backupid_etag.insert(&key, &0u64.to_be_bytes())?;
+                }
+
+                self.backupid_prune_due.remove(&key)?;
+
+                Ok(())
+            })();
+
+            match result {
+                Ok(()) => pruned += 1,
+                Err(e) => tracing::error!("Failed to prune a due key backup version: {e}"),
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    pub fn delete_backup(&self, user_id: &UserId, version: &str) -> Result<()> {
+        // `delete_all_keys` reads the current etag to fold out the removed keys' content hashes,
+        // then writes the resulting (zero) digest back — so it must run before `backupid_etag`
+        // itself is removed below, or it would leave a dangling etag row with no matching
+        // `backupid_algorithm` entry for a version that no longer exists.
+        self.delete_all_keys(user_id, version)?;
+
+        self.backupid_algorithm.remove(&backup_key(user_id, version))?;
+        self.backupid_etag.remove(&backup_key(user_id, version))?;
+        self.backupid_metarev.remove(&backup_key(user_id, version))?;
+        self.backupid_keycount.remove(&backup_key(user_id, version))?;
+        self.backupid_bytesused.remove(&backup_key(user_id, version))?;
+        self.backupid_prune_due.remove(&backup_key(user_id, version))?;
+
+        Ok(())
+    }
+
+    /// Every version a user has ever created, oldest first, regardless of which one is current.
+    pub fn list_versions(&self, user_id: &UserId) -> Result<Vec<String>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.backupid_algorithm
+            .scan_prefix(prefix)
+            .map(|(key, _)| {
+                utils::string_from_bytes(
+                    key.rsplit(|&b| b == 0xff).next().expect("rsplit always yields once"),
+                )
+                .map_err(|_| Error::bad_database("backupid_algorithm key is invalid."))
+            })
+            .collect()
+    }
+
+    /// Deletes every backup version (and its key data) a user owns. Used by the `backups delete`
+    /// admin command and wrapped by `client_server::account::reclaim_key_backups_on_deactivation`;
+    /// account deactivation does not call either, so it leaves a user's backups in place.
+    pub fn delete_all_backups_for_user(&self, user_id: &UserId) -> Result<()> {
+        for version in self.list_versions(user_id)? {
+            self.delete_backup(user_id, &version)?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates a backup version's `auth_data`. The key-content digest half of the etag is
+    /// untouched, but `backupid_metarev` is bumped so `get_etag` still changes: the Matrix spec
+    /// requires a version's `etag` to change whenever the backup (including its metadata) is
+    /// modified.
+    pub fn update_backup(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        backup_metadata: &Raw<BackupAlgorithm>,
+        globals: &Globals,
+    ) -> Result<()> {
+        if self.get_backup(user_id, version)?.is_none() {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Tried to update nonexistent backup."));
+        }
+
+        self.backupid_algorithm.insert(
+            &backup_key(user_id, version),
+            &serde_json::to_vec(backup_metadata).expect("BackupAlgorithm::to_vec always works"),
+        )?;
+        self.backupid_metarev.insert(
+            &backup_key(user_id, version),
+            &globals.next_count()?.to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_latest_backup_version(&self, user_id: &UserId) -> Result<Option<String>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.backupid_algorithm
+            .scan_prefix(prefix)
+            .last()
+            .map(|(key, _)| {
+                utils::string_from_bytes(
+                    key.rsplit(|&b| b == 0xff).next().expect("rsplit always yields once"),
+                )
+                .map_err(|_| Error::bad_database("backupid_algorithm key is invalid."))
+            })
+            .transpose()
+    }
+
+    pub fn get_latest_backup(&self, user_id: &UserId) -> Result<Option<(String, Raw<BackupAlgorithm>)>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.backupid_algorithm
+            .scan_prefix(prefix)
+            .last()
+            .map(|(key, value)| {
+                let version = utils::string_from_bytes(
+                    key.rsplit(|&b| b == 0xff).next().expect("rsplit always yields once"),
+                )
+                .map_err(|_| Error::bad_database("backupid_algorithm key is invalid."))?;
+
+                Ok((
+                    version,
+                    serde_json::from_slice(&value)
+                        .map_err(|_| Error::bad_database("backupid_algorithm value is invalid."))?,
+                ))
+            })
+            .transpose()
+    }
+
+    pub fn get_backup(&self, user_id: &UserId, version: &str) -> Result<Option<Raw<BackupAlgorithm>>> {
+        self.backupid_algorithm
+            .get(&backup_key(user_id, version))?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("backupid_algorithm value is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Total `(room, session)` key count across every version of `user_id`'s backup — quotas are
+    /// per user, not per version, since a superseded version keeps all its keys until it's pruned
+    /// (off by default). Summed from the per-version `backupid_keycount` caches, so this is one
+    /// point read per version rather than a scan of every version's key data.
+    fn total_keys_for_user(&self, user_id: &UserId) -> Result<u64> {
+        let mut total = 0u64;
+        for version in self.list_versions(user_id)? {
+            total += self.count_keys(user_id, &version)? as u64;
+        }
+        Ok(total)
+    }
+
+    /// Total `session_data` bytes across every version of `user_id`'s backup. See
+    /// `total_keys_for_user`.
+    fn total_bytes_for_user(&self, user_id: &UserId) -> Result<u64> {
+        let mut total = 0u64;
+        for version in self.list_versions(user_id)? {
+            total += self.total_bytes(user_id, &version)?;
+        }
+        Ok(total)
+    }
+
+    /// Checks whether adding every key in `keys` to this backup would stay within the server's
+    /// configured per-user quotas, without inserting anything.
+    ///
+    /// Route handlers that add a whole batch of keys in a single request call this before
+    /// looping over `add_key`, so a batch that would blow the quota is rejected atomically
+    /// instead of partially landing (the earlier keys in the loop would otherwise already be
+    /// persisted by the time `add_key` hit the limit on a later one).
+    pub fn check_batch_quota<'a>(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        keys: impl IntoIterator<Item = (&'a RoomId, &'a str, &'a Raw<KeyBackupData>)>,
+    ) -> Result<()> {
+        if self.max_keys_per_user == 0 && self.max_bytes_per_user == 0 {
+            return Ok(());
+        }
+
+        let mut new_sessions = 0u64;
+        let mut byte_delta: i64 = 0;
+
+        for (room_id, session_id, key_data) in keys {
+            let full_key = backup_key_full(user_id, version, room_id, session_id);
+            let new_bytes =
+                serde_json::to_vec(key_data).expect("KeyBackupData::to_vec always works").len() as u64;
+
+            match self.backupkeyid_backup.get(&full_key)? {
+                Some(existing) => byte_delta += new_bytes as i64 - existing.len() as i64,
+                None => {
+                    new_sessions += 1;
+                    byte_delta += new_bytes as i64;
+                }
+            }
+        }
+
+        if self.max_keys_per_user != 0 {
+            let total_keys = self.total_keys_for_user(user_id)?;
+            if total_keys + new_sessions > self.max_keys_per_user {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(uint!(60_000)),
+                    },
+                    "Key backup session limit exceeded for this user.",
+                ));
+            }
+        }
+
+        if self.max_bytes_per_user != 0 {
+            let total_bytes = self.total_bytes_for_user(user_id)? as i64;
+            if total_bytes + byte_delta > self.max_bytes_per_user as i64 {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(uint!(60_000)),
+                    },
+                    "Key backup storage quota exceeded for this user.",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a key to a backup, enforcing the per-user quotas configured for the server.
+    ///
+    /// Returns `Error::BadRequest(ErrorKind::LimitExceeded { .. }, ..)` without inserting
+    /// anything if the addition would push the user's key count or total payload size — summed
+    /// across every version of their backup, not just this one, since a superseded version keeps
+    /// its keys until pruned — over their configured limit (`0` disables the respective limit).
+    /// The cached counters in `backupid_keycount`/`backupid_bytesused` make this check a handful
+    /// of point reads instead of a scan of every version's key data.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_key(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        room_id: &RoomId,
+        session_id: &str,
+        key_data: &Raw<KeyBackupData>,
+    ) -> Result<()> {
+        if self.get_backup(user_id, version)?.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Tried to update nonexistent backup.",
+            ));
+        }
+
+        let full_key = backup_key_full(user_id, version, room_id, session_id);
+        let new_bytes = serde_json::to_vec(key_data).expect("KeyBackupData::to_vec always works");
+
+        let existing = self.backupkeyid_backup.get(&full_key)?;
+        let existing_bytes = existing.as_ref().map(Vec::len).unwrap_or(0) as u64;
+        let is_new_session = existing.is_none();
+
+        let current_count = self.count_keys(user_id, version)?;
+        let current_bytes = self.total_bytes(user_id, version)?;
+
+        if self.max_keys_per_user != 0 && is_new_session {
+            let total_keys = self.total_keys_for_user(user_id)?;
+            if total_keys + 1 > self.max_keys_per_user {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(uint!(60_000)),
+                    },
+                    "Key backup session limit exceeded for this user.",
+                ));
+            }
+        }
+
+        let new_total_bytes = current_bytes - existing_bytes + new_bytes.len() as u64;
+        if self.max_bytes_per_user != 0 {
+            let total_bytes_for_user = self.total_bytes_for_user(user_id)? as i64;
+            let new_user_total = total_bytes_for_user - existing_bytes as i64 + new_bytes.len() as i64;
+            if new_user_total > self.max_bytes_per_user as i64 {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(uint!(60_000)),
+                    },
+                    "Key backup storage quota exceeded for this user.",
+                ));
+            }
+        }
+
+        self.backupkeyid_backup.insert(&full_key, &new_bytes)?;
+
+        if is_new_session {
+            self.backupid_keycount
+                .insert(&backup_key(user_id, version), &(current_count as u64 + 1).to_be_bytes())?;
+        }
+        self.backupid_bytesused
+            .insert(&backup_key(user_id, version), &new_total_bytes.to_be_bytes())?;
+
+        // Fold the new session's content hash in and the overwritten one (if any) back out, so
+        // the etag stays a digest over exactly the triples currently in the backup, independent
+        // of insertion order, without ever re-hashing the whole backup.
+        let mut digest = self.current_digest(user_id, version)?;
+        if let Some(old_bytes) = &existing {
+            digest ^= content_hash(room_id, session_id, old_bytes);
+        }
+        digest ^= content_hash(room_id, session_id, &new_bytes);
+        self.backupid_etag
+            .insert(&backup_key(user_id, version), &digest.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn current_digest(&self, user_id: &UserId, version: &str) -> Result<u64> {
+        self.backupid_etag
+            .get(&backup_key(user_id, version))?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("backupid_etag value is invalid."))
+            })
+            .transpose()
+            .map(|digest| digest.unwrap_or(0))
+    }
+
+    pub fn count_keys(&self, user_id: &UserId, version: &str) -> Result<usize> {
+        match self.backupid_keycount.get(&backup_key(user_id, version))? {
+            Some(bytes) => Ok(utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("backupid_keycount value is invalid."))? as usize),
+            // No cached value (backup predates the quota cache, or a `delete_*` call cleared it):
+            // fall back to a scan once and seed the cache so subsequent calls are cheap again.
+            None => {
+                let count = self.get_all(user_id, version)?.values().map(BTreeMap::len).sum::<usize>();
+                self.backupid_keycount
+                    .insert(&backup_key(user_id, version), &(count as u64).to_be_bytes())?;
+                Ok(count)
+            }
+        }
+    }
+
+    fn total_bytes(&self, user_id: &UserId, version: &str) -> Result<u64> {
+        match self.backupid_bytesused.get(&backup_key(user_id, version))? {
+            Some(bytes) => utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("backupid_bytesused value is invalid.")),
+            None => {
+                let total = self
+                    .get_all(user_id, version)?
+                    .values()
+                    .flat_map(BTreeMap::values)
+                    .map(|raw| raw.json().get().len() as u64)
+                    .sum();
+                self.backupid_bytesused
+                    .insert(&backup_key(user_id, version), &total.to_be_bytes())?;
+                Ok(total)
+            }
+        }
+    }
+
+    /// A digest over every `(room_id, session_id, session_data)` triple currently stored in this
+    /// backup version, combined with its metadata revision, formatted as a fixed-width hex
+    /// string. Two versions (even across users) with identical keys have the same etag as long as
+    /// neither has gone through `update_backup`, so clients can skip re-downloading a backup whose
+    /// etag they've already seen. `auth_data` itself is not hashed: `update_backup` sets a fresh
+    /// server-wide revision, so the etag still changes on a metadata-only `update_backup` call, as
+    /// the spec requires.
+    pub fn get_etag(&self, user_id: &UserId, version: &str) -> Result<String> {
+        if self.backupid_etag.get(&backup_key(user_id, version))?.is_none() {
+            return Err(Error::bad_database("Backup does not exist"));
+        }
+
+        let metarev = self
+            .backupid_metarev
+            .get(&backup_key(user_id, version))?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("backupid_metarev value is invalid."))
+            })
+            .transpose()?
+            .unwrap_or(0);
+
+        Ok(format!("{:016x}{:016x}", metarev, self.current_digest(user_id, version)?))
+    }
+
+    pub fn get_all(
+        &self,
+        user_id: &UserId,
+        version: &str,
+    ) -> Result<BTreeMap<OwnedRoomId, BTreeMap<String, Raw<KeyBackupData>>>> {
+        let mut rooms = BTreeMap::new();
+
+        let mut prefix = backup_key(user_id, version);
+        prefix.push(0xff);
+
+        for (key, value) in self.backupkeyid_backup.scan_prefix(prefix) {
+            let (room_id, session_id) = split_room_session(&key)?;
+
+            let key_data = serde_json::from_slice(&value)
+                .map_err(|_| Error::bad_database("backupkeyid_backup value is invalid."))?;
+
+            rooms.entry(room_id).or_insert_with(BTreeMap::new).insert(session_id, key_data);
+        }
+
+        Ok(rooms)
+    }
+
+    pub fn get_room(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Raw<KeyBackupData>>> {
+        let mut prefix = backup_key(user_id, version);
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.as_bytes());
+        prefix.push(0xff);
+
+        self.backupkeyid_backup
+            .scan_prefix(prefix)
+            .map(|(key, value)| {
+                let session_id = utils::string_from_bytes(
+                    key.rsplit(|&b| b == 0xff).next().expect("rsplit always yields once"),
+                )
+                .map_err(|_| Error::bad_database("backupkeyid_backup session_id is invalid."))?;
+
+                let key_data = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("backupkeyid_backup value is invalid."))?;
+
+                Ok((session_id, key_data))
+            })
+            .collect()
+    }
+
+    pub fn get_session(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<Raw<KeyBackupData>>> {
+        self.backupkeyid_backup
+            .get(&backup_key_full(user_id, version, room_id, session_id))?
+            .map(|value| {
+                serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("backupkeyid_backup value is invalid."))
+            })
+            .transpose()
+    }
+
+    pub fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()> {
+        let mut prefix = backup_key(user_id, version);
+        prefix.push(0xff);
+
+        let mut digest = self.current_digest(user_id, version)?;
+        for (key, value) in self.backupkeyid_backup.scan_prefix(prefix) {
+            let (room_id, session_id) = split_room_session(&key)?;
+            digest ^= content_hash(&room_id, &session_id, &value);
+            self.backupkeyid_backup.remove(&key)?;
+        }
+
+        self.backupid_etag
+            .insert(&backup_key(user_id, version), &digest.to_be_bytes())?;
+        self.backupid_keycount.remove(&backup_key(user_id, version))?;
+        self.backupid_bytesused.remove(&backup_key(user_id, version))?;
+
+        Ok(())
+    }
+
+    pub fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId) -> Result<()> {
+        let mut prefix = backup_key(user_id, version);
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.as_bytes());
+        prefix.push(0xff);
+
+        let mut digest = self.current_digest(user_id, version)?;
+        for (key, value) in self.backupkeyid_backup.scan_prefix(prefix) {
+            let (room_id, session_id) = split_room_session(&key)?;
+            digest ^= content_hash(&room_id, &session_id, &value);
+            self.backupkeyid_backup.remove(&key)?;
+        }
+        self.backupid_etag
+            .insert(&backup_key(user_id, version), &digest.to_be_bytes())?;
+
+        // The key-count/byte-total caches are now stale for this version; clearing them forces
+        // count_keys/total_bytes to rescan once and reseed the cache on next use.
+        self.backupid_keycount.remove(&backup_key(user_id, version))?;
+        self.backupid_bytesused.remove(&backup_key(user_id, version))?;
+
+        Ok(())
+    }
+
+    pub fn delete_room_key(&self, user_id: &UserId, version: &str, room_id: &RoomId, session_id: &str) -> Result<()> {
+        let full_key = backup_key_full(user_id, version, room_id, session_id);
+
+        if let Some(value) = self.backupkeyid_backup.get(&full_key)? {
+            let digest = self.current_digest(user_id, version)? ^ content_hash(room_id, session_id, &value);
+            self.backupid_etag
+                .insert(&backup_key(user_id, version), &digest.to_be_bytes())?;
+        }
+        self.backupkeyid_backup.remove(&full_key)?;
+
+        self.backupid_keycount.remove(&backup_key(user_id, version))?;
+        self.backupid_bytesused.remove(&backup_key(user_id, version))?;
+
+        Ok(())
+    }
+}
+
+/// Splits a `backupkeyid_backup` key into its `room_id` and `session_id` components.
+fn split_room_session(key: &[u8]) -> Result<(OwnedRoomId, String)> {
+    let mut parts = key.rsplit(|&b| b == 0xff);
+    let session_id = utils::string_from_bytes(parts.next().expect("rsplit always yields once"))
+        .map_err(|_| Error::bad_database("backupkeyid_backup session_id is invalid."))?;
+    let room_id = utils::string_from_bytes(parts.next().expect("backupkeyid_backup key is malformed"))
+        .map_err(|_| Error::bad_database("backupkeyid_backup room_id is invalid."))?;
+    let room_id =
+        OwnedRoomId::try_from(room_id).map_err(|_| Error::bad_database("backupkeyid_backup room_id is invalid."))?;
+
+    Ok((room_id, session_id))
+}
+
+/// Content hash over one `(room_id, session_id, session_data)` triple. The etag in
+/// `backupid_etag` is the XOR of this over every triple in a backup version, which lets it be
+/// updated incrementally: XOR a triple's hash in to add it, XOR the same hash in again to remove
+/// it.
+fn content_hash(room_id: &RoomId, session_id: &str, session_data: &[u8]) -> u64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(room_id.as_bytes());
+    hasher.update(&[0xff]);
+    hasher.update(session_id.as_bytes());
+    hasher.update(&[0xff]);
+    hasher.update(session_data);
+
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest.as_bytes()[..8].try_into().expect("blake3 digest is at least 8 bytes"))
+}
+
+fn backup_key(user_id: &UserId, version: &str) -> Vec<u8> {
+    let mut key = user_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(version.as_bytes());
+    key
+}
+
+fn backup_key_full(user_id: &UserId, version: &str, room_id: &RoomId, session_id: &str) -> Vec<u8> {
+    let mut key = backup_key(user_id, version);
+    key.push(0xff);
+    key.extend_from_slice(room_id.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(session_id.as_bytes());
+    key
+}